@@ -1,11 +1,14 @@
 use std::collections::HashMap;
 use std::error::Error;
+use std::fmt;
 
-use rusoto_core::credential::{ChainProvider, ProvideAwsCredentials};
+use rusoto_core::credential::{AwsCredentials, ChainProvider, ProvideAwsCredentials};
 use rusoto_core::param::{Params, ServiceParams};
+use rusoto_core::request::HttpClient;
 use rusoto_core::Region;
 use rusoto_signature::SignedRequest;
-use serde_json::json;
+use rusoto_sts::{AssumeRoleRequest, Sts, StsClient};
+use serde::{Deserialize, Serialize};
 
 /// The authentication options to be passed into the main auth function
 #[derive(Debug)]
@@ -19,13 +22,327 @@ pub struct Parameters {
     pub role: String,
     /// The full Vault server address and port to send the request
     pub vault_address: String,
+    /// The AWS region the STS `GetCallerIdentity` request is signed
+    /// for, matching whatever `sts_region` the Vault AWS auth mount is
+    /// configured with. Defaults to `us-east-1` when unset
+    pub sts_region: Option<Region>,
+    /// A custom STS endpoint to sign and send the request against,
+    /// matching the Vault AWS auth mount's `sts_endpoint`. When unset,
+    /// defaults to the regional `https://sts.<region>.amazonaws.com/`
+    /// endpoint for `sts_region`, or to the global
+    /// `https://sts.amazonaws.com/` endpoint for `us-east-1` (the
+    /// default region).
+    /// Since the signature region and the endpoint host must agree,
+    /// `sts_region` must also be set whenever this is set
+    pub sts_endpoint: Option<String>,
+    /// The ARN of an IAM role to assume via STS `AssumeRole` before
+    /// signing the `GetCallerIdentity` request, for authenticating as
+    /// an assumed role rather than the ambient credentials
+    pub assume_role_arn: Option<String>,
+    /// The session name to assign to the assumed role, used as the
+    /// `RoleSessionName` on the STS `AssumeRole` call
+    pub assume_role_session_name: Option<String>,
+    /// How long the assumed role's temporary credentials are valid
+    /// for, in seconds (900-43200). Defaults to 3600 when unset
+    pub duration_seconds: Option<i64>,
+}
+
+/// Errors surfaced by [`authenticate`], distinguishing a failure to
+/// assume the configured role from a failure of the Vault login call
+#[derive(Debug)]
+pub enum AuthError {
+    /// The STS `AssumeRole` call failed before a login could be attempted
+    AssumeRole(Box<dyn Error>),
+    /// Building the IAM payload or calling the Vault login endpoint failed
+    Vault(Box<dyn Error>),
+    /// Vault responded with a non-2xx status, carrying its `errors` array
+    VaultLogin(Vec<String>),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::AssumeRole(e) => write!(f, "failed to assume role: {}", e),
+            AuthError::Vault(e) => write!(f, "vault login failed: {}", e),
+            AuthError::VaultLogin(errors) => write!(f, "vault login failed: {}", errors.join(", ")),
+        }
+    }
+}
+
+impl Error for AuthError {}
+
+/// The `auth` block of a successful Vault login response
+#[derive(Debug, Deserialize)]
+pub struct VaultAuth {
+    /// The client token to use for subsequent authenticated requests
+    pub client_token: String,
+    /// The accessor associated with the client token
+    pub accessor: String,
+    /// The policies attached to the token, including inherited ones
+    pub policies: Vec<String>,
+    /// The policies attached directly to the token
+    pub token_policies: Vec<String>,
+    /// Arbitrary metadata Vault attached to the token
+    pub metadata: HashMap<String, String>,
+    /// The number of seconds the token's lease is valid for
+    pub lease_duration: u64,
+    /// Whether the token's lease can be renewed
+    pub renewable: bool,
+    /// The identity entity ID the token is associated with
+    pub entity_id: String,
+}
+
+/// The body of a successful Vault login response
+#[derive(Debug, Deserialize)]
+struct VaultLoginResponse {
+    auth: VaultAuth,
+}
+
+/// The body Vault returns on a non-2xx response
+#[derive(Debug, Deserialize)]
+struct VaultErrorResponse {
+    errors: Vec<String>,
+}
+
+/// (De)serializes a field as the base64 encoding of its plain string
+/// value, which is the wire form Vault's AWS auth engine expects for
+/// `iam_request_url` and `iam_request_body`
+mod base64_string {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &str, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(value))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        let bytes = base64::decode(encoded).map_err(de::Error::custom)?;
+        String::from_utf8(bytes).map_err(de::Error::custom)
+    }
+}
+
+/// (De)serializes `iam_request_headers` as the base64 encoding of its
+/// JSON representation, which is the wire form Vault's AWS auth engine
+/// expects
+mod base64_headers {
+    use std::collections::HashMap;
+
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(
+        value: &HashMap<String, Vec<String>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let json = serde_json::to_string(value).map_err(serde::ser::Error::custom)?;
+        serializer.serialize_str(&base64::encode(json))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<HashMap<String, Vec<String>>, D::Error> {
+        let encoded = String::deserialize(deserializer)?;
+        let bytes = base64::decode(encoded).map_err(de::Error::custom)?;
+        serde_json::from_slice(&bytes).map_err(de::Error::custom)
+    }
+}
+
+/// The AWS IAM `GetCallerIdentity` login payload that Vault's AWS
+/// authentication engine expects, decoupled from any particular HTTP
+/// client so it can be inspected, cached, or sent over a transport
+/// other than `reqwest`. Fields hold their plain, inspectable values;
+/// the base64 wire encoding Vault expects is applied on serialization
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AwsAuthIamPayload {
+    /// The HTTP method used to sign and send the STS request, always `POST`
+    pub iam_http_request_method: String,
+    /// The STS endpoint the signed request was built for
+    #[serde(with = "base64_string")]
+    pub iam_request_url: String,
+    /// The urlencoded `GetCallerIdentity` request body
+    #[serde(with = "base64_string")]
+    pub iam_request_body: String,
+    /// The AWS4 signed request headers
+    #[serde(with = "base64_headers")]
+    pub iam_request_headers: HashMap<String, Vec<String>>,
+    /// The role in Vault to authenticate as under the AWS engine
+    pub role: String,
+}
+
+impl AwsAuthIamPayload {
+    /// Builds the AWS4 signed `GetCallerIdentity` request for the argued
+    /// role using the argued credentials, signed for the optionally
+    /// argued STS region and endpoint (the region defaults to
+    /// `us-east-1`; the endpoint defaults to that region's STS endpoint,
+    /// which is the global endpoint for `us-east-1`)
+    pub async fn new(
+        role: &str,
+        iam_server_id: &Option<String>,
+        sts_region: &Option<Region>,
+        sts_endpoint: &Option<String>,
+        credentials: &AwsCredentials,
+    ) -> Result<Self, Box<dyn Error>> {
+        if sts_endpoint.is_some() && sts_region.is_none() {
+            return Err("sts_region must be set when sts_endpoint is provided, \
+                since the signed request's region and the endpoint host must agree"
+                .into());
+        }
+
+        let region = resolved_sts_region(sts_region);
+        let endpoint = sts_endpoint
+            .clone()
+            .unwrap_or_else(|| default_sts_endpoint(&region));
+
+        let signed_request = {
+            let mut req = SignedRequest::new("POST", "sts", &region, "/");
+            req.set_hostname(Some(endpoint_host(&endpoint)));
+
+            if let Some(id) = iam_server_id {
+                req.add_header("X-Vault-AWS-IAM-Server-ID", id);
+            }
+
+            let mut params = Params::new();
+            params.put("Action", "GetCallerIdentity");
+            params.put("Version", "2011-06-15");
+
+            req.set_payload(Some(serde_urlencoded::to_string(&params)?));
+            req.set_content_type(String::from("application/x-www-form-urlencoded"));
+            req.sign(credentials);
+            req
+        };
+
+        let headers = {
+            let mut headers = HashMap::<String, Vec<String>>::new();
+            for (key, values) in signed_request.headers() {
+                let entries = values
+                    .iter()
+                    .map(|v| String::from_utf8(v.to_owned()).unwrap())
+                    .collect::<Vec<String>>();
+                headers.insert(key.to_owned(), entries);
+            }
+            headers
+        };
+
+        Ok(Self {
+            iam_http_request_method: String::from("POST"),
+            iam_request_url: endpoint,
+            iam_request_headers: headers,
+            iam_request_body: String::from("Action=GetCallerIdentity&Version=2011-06-15"),
+            role: role.to_owned(),
+        })
+    }
+}
+
+/// Resolves the STS region to sign against, defaulting to `us-east-1`
+/// when unset. Shared by payload signing and assume-role signing so
+/// the two can never disagree on the default
+fn resolved_sts_region(sts_region: &Option<Region>) -> Region {
+    sts_region.clone().unwrap_or(Region::UsEast1)
+}
+
+/// Returns the regional STS endpoint for the argued region, falling
+/// back to the global endpoint for `us-east-1`
+fn default_sts_endpoint(region: &Region) -> String {
+    match region {
+        Region::UsEast1 => String::from("https://sts.amazonaws.com/"),
+        _ => format!("https://sts.{}.amazonaws.com/", region.name()),
+    }
+}
+
+/// Extracts the `Host` header value from an STS endpoint URL so the
+/// signed request's host matches the endpoint it will be sent to
+fn endpoint_host(endpoint: &str) -> String {
+    endpoint
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_end_matches('/')
+        .to_owned()
+}
+
+/// Calls STS `AssumeRole` with credentials drawn from the argued
+/// provider to obtain temporary credentials for the argued role, which
+/// are used to sign the `GetCallerIdentity` request in its place
+async fn assume_role<P: ProvideAwsCredentials + Send + Sync + 'static>(
+    region: &Region,
+    role_arn: &str,
+    session_name: &str,
+    duration_seconds: i64,
+    provider: P,
+) -> Result<AwsCredentials, Box<dyn Error>> {
+    if !(900..=43200).contains(&duration_seconds) {
+        return Err("duration_seconds must be between 900 and 43200".into());
+    }
+
+    let client = StsClient::new_with(HttpClient::new()?, provider, region.to_owned());
+    let req = AssumeRoleRequest {
+        role_arn: role_arn.to_owned(),
+        role_session_name: session_name.to_owned(),
+        duration_seconds: Some(duration_seconds),
+        ..Default::default()
+    };
+
+    let creds = client
+        .assume_role(req)
+        .await?
+        .credentials
+        .ok_or("STS did not return temporary credentials")?;
+
+    Ok(AwsCredentials::new(
+        creds.access_key_id,
+        creds.secret_access_key,
+        Some(creds.session_token),
+        None,
+    ))
+}
+
+/// Builds the authentication request payload from the credentials
+/// found in the provider chain (optionally assuming a role first) and
+/// sends it to the designated Vault server to attempt a login for the
+/// argued role
+pub async fn authenticate(params: &Parameters) -> Result<VaultAuth, AuthError> {
+    authenticate_with(params, ChainProvider::new()).await
 }
 
 /// Builds the authentication request payload from the credentials
-/// found in the provider chain and sends it to the designated
-/// Vault server to attempt a login for the argued role
-pub async fn authenticate(params: &Parameters) -> Result<serde_json::Value, Box<dyn Error>> {
-    let payload = new_iam_payload(&params.role, &params.iam_server_id).await?;
+/// supplied by the argued provider (optionally assuming a role first)
+/// and sends it to the designated Vault server to attempt a login for
+/// the argued role. This allows supplying static credentials, a
+/// profile provider, a web-identity/OIDC token file provider, or a
+/// test double in place of the default [`ChainProvider`]
+pub async fn authenticate_with<P: ProvideAwsCredentials + Send + Sync + 'static>(
+    params: &Parameters,
+    provider: P,
+) -> Result<VaultAuth, AuthError> {
+    let region = resolved_sts_region(&params.sts_region);
+
+    let credentials = match &params.assume_role_arn {
+        Some(role_arn) => assume_role(
+            &region,
+            role_arn,
+            params
+                .assume_role_session_name
+                .as_deref()
+                .unwrap_or("vault-iam-auth-rs"),
+            params.duration_seconds.unwrap_or(3600),
+            provider,
+        )
+        .await
+        .map_err(AuthError::AssumeRole)?,
+        None => provider
+            .credentials()
+            .await
+            .map_err(|e| AuthError::Vault(Box::new(e)))?,
+    };
+
+    let payload = AwsAuthIamPayload::new(
+        &params.role,
+        &params.iam_server_id,
+        &params.sts_region,
+        &params.sts_endpoint,
+        &credentials,
+    )
+    .await
+    .map_err(AuthError::Vault)?;
+
     let url = format!(
         "{}/v1/auth/{}/login",
         params.vault_address, params.mount_path
@@ -36,53 +353,79 @@ pub async fn authenticate(params: &Parameters) -> Result<serde_json::Value, Box<
         .header("Accept", "application/json")
         .json(&payload)
         .send()
-        .await?
-        .json::<serde_json::Value>()
-        .await?;
-    Ok(res)
-}
-
-/// Creates the AWS4 signed request headers and the authentication
-/// payload that will be sent to Vault in the login attempt
-async fn new_iam_payload(
-    role: &str,
-    iam_server_id: &Option<String>,
-) -> Result<serde_json::Value, Box<dyn Error>> {
-    let credentials = ChainProvider::new().credentials().await?;
-    let signed_request = {
-        let mut req = SignedRequest::new("POST", "sts", &Region::UsEast1, "/");
-
-        if let Some(id) = iam_server_id {
-            req.add_header("X-Vault-AWS-IAM-Server-ID", id);
-        }
+        .await
+        .map_err(|e| AuthError::Vault(Box::new(e)))?;
 
-        let mut params = Params::new();
-        params.put("Action", "GetCallerIdentity");
-        params.put("Version", "2011-06-15");
+    let status = res.status();
+    let body = res.bytes().await.map_err(|e| AuthError::Vault(Box::new(e)))?;
 
-        req.set_payload(Some(serde_urlencoded::to_string(&params)?));
-        req.set_content_type(String::from("application/x-www-form-urlencoded"));
-        req.sign(&credentials);
-        req
-    };
+    if status.is_success() {
+        let login: VaultLoginResponse =
+            serde_json::from_slice(&body).map_err(|e| AuthError::Vault(Box::new(e)))?;
+        Ok(login.auth)
+    } else {
+        let error: VaultErrorResponse =
+            serde_json::from_slice(&body).map_err(|e| AuthError::Vault(Box::new(e)))?;
+        Err(AuthError::VaultLogin(error.errors))
+    }
+}
 
-    let signed_headers = {
-        let mut headers = HashMap::<String, Vec<String>>::new();
-        for (key, values) in signed_request.headers() {
-            let entries = values
-                .iter()
-                .map(|v| String::from_utf8(v.to_owned()).unwrap())
-                .collect::<Vec<String>>();
-            headers.insert(key.to_owned(), entries);
-        }
-        serde_json::to_string(&headers)?
-    };
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusoto_core::credential::StaticProvider;
+
+    #[test]
+    fn aws_auth_iam_payload_round_trips_through_serde() {
+        let mut headers = HashMap::new();
+        headers.insert(
+            String::from("host"),
+            vec![String::from("sts.amazonaws.com")],
+        );
+
+        let payload = AwsAuthIamPayload {
+            iam_http_request_method: String::from("POST"),
+            iam_request_url: String::from("https://sts.amazonaws.com/"),
+            iam_request_body: String::from("Action=GetCallerIdentity&Version=2011-06-15"),
+            iam_request_headers: headers,
+            role: String::from("my-role"),
+        };
+
+        let json = serde_json::to_string(&payload).unwrap();
+        let decoded: AwsAuthIamPayload = serde_json::from_str(&json).unwrap();
 
-    Ok(json!({
-        "iam_http_request_method": "POST",
-        "iam_request_url": base64::encode(b"https://sts.amazonaws.com/"),
-        "iam_request_headers": base64::encode(signed_headers.as_bytes()),
-        "iam_request_body": base64::encode(b"Action=GetCallerIdentity&Version=2011-06-15"),
-        "role": role
-    }))
+        assert_eq!(decoded.iam_http_request_method, payload.iam_http_request_method);
+        assert_eq!(decoded.iam_request_url, payload.iam_request_url);
+        assert_eq!(decoded.iam_request_body, payload.iam_request_body);
+        assert_eq!(decoded.iam_request_headers, payload.iam_request_headers);
+        assert_eq!(decoded.role, payload.role);
+    }
+
+    #[tokio::test]
+    async fn authenticate_with_signs_using_the_argued_provider() {
+        let provider = StaticProvider::new_minimal(
+            String::from("AKIAEXAMPLE"),
+            String::from("secretexample"),
+        );
+
+        let params = Parameters {
+            iam_server_id: None,
+            mount_path: String::from("aws"),
+            role: String::from("my-role"),
+            vault_address: String::from("http://127.0.0.1:0"),
+            sts_region: None,
+            sts_endpoint: None,
+            assume_role_arn: None,
+            assume_role_session_name: None,
+            duration_seconds: None,
+        };
+
+        // The static provider never touches the environment, so if
+        // credential resolution succeeded the failure below must come
+        // from the (deliberately unreachable) Vault request instead.
+        match authenticate_with(&params, provider).await {
+            Err(AuthError::Vault(e)) if e.is::<reqwest::Error>() => {}
+            other => panic!("expected AuthError::Vault once credentials resolved, got {:?}", other),
+        }
+    }
 }